@@ -0,0 +1,150 @@
+//! Configurable connection setup.
+//!
+//! `test_helpers::run_test` hardcodes loopback, the default port, the mainnet
+//! identifier, and reuses our own public key as the server's — so it can only
+//! ever talk to a local mainnet server whose identity equals ours.
+//! [`ConnectOptions`] lets a caller choose the peer address, the network
+//! identifier (for alt-nets and test-nets), and the remote server's public key
+//! independently of our own identity, then performs the secret handshake and
+//! hands back a driving [`RpcClient`].
+
+use std::net::SocketAddr;
+
+use box_stream::BoxDuplex;
+use futures::prelude::*;
+use secret_stream::OwningClient;
+use sodiumoxide::crypto::box_;
+use ssb_common::{NetworkIdentifier, MAINNET_IDENTIFIER};
+use tokio::executor::current_thread;
+use tokio::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use client::RpcClient;
+use server::{serve, Handlers};
+
+/// Our own identity: the long-term keypair used in the handshake.
+#[derive(Clone)]
+pub struct Identity {
+    pub pk: box_::PublicKey,
+    pub sk: box_::SecretKey,
+}
+
+/// How to reach and authenticate a peer.
+///
+/// Build with [`ConnectOptions::new`], which takes the peer address, optionally
+/// override the network with [`ConnectOptions::network`], then pass to
+/// [`connect`].
+#[derive(Clone)]
+pub struct ConnectOptions {
+    addr: SocketAddr,
+    network: NetworkIdentifier,
+    server_pk: box_::PublicKey,
+    identity: Identity,
+}
+
+impl ConnectOptions {
+    /// Connects to `server_pk` at `addr` using `identity`, on the mainnet by
+    /// default. Use [`ConnectOptions::network`] to target an alt-net.
+    pub fn new(addr: SocketAddr, server_pk: box_::PublicKey, identity: Identity) -> ConnectOptions {
+        ConnectOptions {
+            addr,
+            network: MAINNET_IDENTIFIER,
+            server_pk,
+            identity,
+        }
+    }
+
+    /// Overrides the network identifier, to reach a test-net or private net.
+    pub fn network(mut self, network: NetworkIdentifier) -> ConnectOptions {
+        self.network = network;
+        self
+    }
+}
+
+/// Errors that can occur while establishing a connection.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The TCP connection could not be established.
+    Tcp(std::io::Error),
+    /// The secret handshake failed.
+    Handshake(secret_stream::ClientError),
+}
+
+/// Opens a TCP connection to the configured peer, runs the secret handshake,
+/// and resolves to a driving [`RpcClient`] over the encrypted duplex.
+///
+/// Peer-initiated requests are answered with an empty [`Handlers`] registry (so
+/// they receive a `METHOD_NOT_FOUND` error frame rather than hanging); use
+/// [`connect_with_handlers`] to register real handlers.
+pub fn connect(
+    opts: ConnectOptions,
+) -> impl Future<Item = RpcClient<BoxDuplex<TcpStream>>, Error = ConnectError> {
+    connect_with_handlers(opts, Handlers::new())
+}
+
+/// Like [`connect`], but serves incoming requests through `handlers`, making the
+/// connection a symmetric peer that can both call and answer.
+pub fn connect_with_handlers(
+    opts: ConnectOptions,
+    handlers: Handlers,
+) -> impl Future<Item = RpcClient<BoxDuplex<TcpStream>>, Error = ConnectError> {
+    let addr = opts.addr;
+    TcpStream::connect(&addr)
+        .map_err(ConnectError::Tcp)
+        .and_then(move |tcp| connect_over_with_handlers(tcp, opts, handlers))
+}
+
+/// Runs the secret handshake and muxrpc stack over an already-established byte
+/// stream, resolving to a driving [`RpcClient`].
+///
+/// Generalizing over `T` lets the handshake/muxrpc layers run over any carrier —
+/// an in-memory duplex pipe for unit tests, a Unix socket, or a tunneled stream —
+/// not just [`TcpStream`]. Incoming requests are answered with an empty
+/// [`Handlers`] registry; use [`connect_over_with_handlers`] to register real
+/// handlers.
+pub fn connect_over<T>(
+    io: T,
+    opts: ConnectOptions,
+) -> impl Future<Item = RpcClient<BoxDuplex<T>>, Error = ConnectError>
+where
+    T: 'static + AsyncRead + AsyncWrite,
+{
+    connect_over_with_handlers(io, opts, Handlers::new())
+}
+
+/// Like [`connect_over`], but serves the connection's incoming half through
+/// `handlers` so the peer can call us over the same carrier.
+pub fn connect_over_with_handlers<T>(
+    io: T,
+    opts: ConnectOptions,
+    handlers: Handlers,
+) -> impl Future<Item = RpcClient<BoxDuplex<T>>, Error = ConnectError>
+where
+    T: 'static + AsyncRead + AsyncWrite,
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let ConnectOptions {
+        network,
+        server_pk,
+        identity,
+        ..
+    } = opts;
+
+    OwningClient::new(
+        io,
+        network,
+        identity.pk,
+        identity.sk,
+        ephemeral_pk,
+        ephemeral_sk,
+        server_pk,
+    )
+    .map_err(|(err, _)| ConnectError::Handshake(err))
+    .map(move |connection| {
+        let (client, rpc_in) = RpcClient::new(connection.unwrap());
+        // Drain and dispatch the incoming half on the same executor, so a peer
+        // calling us is served instead of left hanging on an unread stream.
+        current_thread::spawn(serve(rpc_in, handlers));
+        client
+    })
+}
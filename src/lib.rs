@@ -0,0 +1,28 @@
+//! A muxrpc client/server for Secure Scuttlebutt connections.
+
+extern crate box_stream;
+extern crate futures;
+extern crate muxrpc;
+extern crate secret_stream;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate sodiumoxide;
+extern crate ssb_common;
+extern crate ssb_keyfile;
+extern crate tokio;
+extern crate tokio_io;
+
+pub mod client;
+pub mod connect;
+pub mod server;
+
+#[cfg(test)]
+mod test_helpers;
+
+pub use client::{Incoming, RpcClient, RpcError, RequestId, SourceStream};
+pub use connect::{
+    connect, connect_over, connect_over_with_handlers, connect_with_handlers, ConnectError,
+    ConnectOptions, Identity,
+};
+pub use server::{serve, Handlers};
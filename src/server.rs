@@ -0,0 +1,151 @@
+//! Server-side muxrpc: consume the incoming half of a connection and dispatch
+//! each request to a registered handler.
+//!
+//! SSB connections are symmetric — the peer can call *us* — so a connection is
+//! not complete until something drains the `RpcIn` stream that
+//! [`RpcClient::new`](crate::client::RpcClient::new) hands back alongside the
+//! client. [`Handlers`] maps a method path
+//! plus request type to a boxed closure, and [`serve`] feeds the incoming stream
+//! through it, replying with a muxrpc error frame when no handler matches.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use futures::prelude::*;
+use muxrpc::{IncomingRpc, RpcIn};
+use serde_json::Value;
+use tokio::executor::current_thread;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A method path such as `["blobs", "get"]`.
+pub type Method = Vec<String>;
+
+/// Handles an `async` request: given the decoded argument value, produces a
+/// future resolving to the response body (or an error body).
+pub type AsyncHandler = Box<FnMut(Value) -> Box<Future<Item = Value, Error = Value>>>;
+
+/// Handles a `source` request: given the decoded argument value, produces a
+/// stream of response bodies.
+pub type SourceHandler = Box<FnMut(Value) -> Box<Stream<Item = Value, Error = Value>>>;
+
+/// A registry of handlers keyed by method path, split by request type the same
+/// way muxrpc splits its outgoing calls.
+#[derive(Default)]
+pub struct Handlers {
+    async: HashMap<Method, AsyncHandler>,
+    source: HashMap<Method, SourceHandler>,
+}
+
+impl Handlers {
+    /// Creates an empty registry.
+    pub fn new() -> Handlers {
+        Handlers::default()
+    }
+
+    /// Registers a handler for `async` calls to `method`.
+    pub fn async<F>(&mut self, method: &[&str], handler: F) -> &mut Handlers
+    where
+        F: 'static + FnMut(Value) -> Box<Future<Item = Value, Error = Value>>,
+    {
+        self.async.insert(path(method), Box::new(handler));
+        self
+    }
+
+    /// Registers a handler for `source` calls to `method`.
+    pub fn source<F>(&mut self, method: &[&str], handler: F) -> &mut Handlers
+    where
+        F: 'static + FnMut(Value) -> Box<Stream<Item = Value, Error = Value>>,
+    {
+        self.source.insert(path(method), Box::new(handler));
+        self
+    }
+}
+
+fn path(method: &[&str]) -> Method {
+    method.iter().map(|s| s.to_string()).collect()
+}
+
+/// Drains the incoming half of a connection, dispatching each request through
+/// `handlers`. Resolves when the peer closes the incoming stream.
+pub fn serve<R, W>(
+    rpc_in: RpcIn<R, W>,
+    mut handlers: Handlers,
+) -> impl Future<Item = (), Error = ()>
+where
+    R: 'static + AsyncRead,
+    W: 'static + AsyncWrite,
+{
+    rpc_in
+        .for_each(move |incoming| {
+            dispatch(&mut handlers, incoming);
+            Ok(())
+        })
+        .map_err(|_| ())
+}
+
+/// Routes a single incoming request to its handler, or replies with an error
+/// frame when no handler is registered for the method.
+fn dispatch(handlers: &mut Handlers, incoming: IncomingRpc) {
+    match incoming {
+        IncomingRpc::Async(method, args, responder) => {
+            match handlers.async.get_mut(&path_of(&method)) {
+                Some(handler) => {
+                    let response = handler(args).then(move |res| {
+                        let _ = match res {
+                            Ok(value) => responder.respond(&value),
+                            Err(err) => responder.respond_error(&err),
+                        };
+                        Ok(())
+                    });
+                    current_thread::spawn(response);
+                }
+                None => {
+                    let _ = responder.respond_error(&no_handler(&method));
+                }
+            }
+        }
+        IncomingRpc::Source(method, args, responder) => {
+            match handlers.source.get_mut(&path_of(&method)) {
+                Some(handler) => {
+                    let items = handler(args).then(move |res| match res {
+                        Ok(value) => responder.send(&value).map_err(|_| ()),
+                        Err(err) => {
+                            let _ = responder.error(&err);
+                            Err(())
+                        }
+                    });
+                    current_thread::spawn(items.for_each(|_| Ok(())).then(|_| Ok(())));
+                }
+                None => {
+                    let _ = responder.error(&no_handler(&method));
+                }
+            }
+        }
+        // No duplex/sink handlers are registered, but the peer still expects an
+        // answer: reply with an error frame on the outgoing half so the call
+        // fails fast instead of hanging, exactly as the no-handler async/source
+        // paths do above.
+        IncomingRpc::Duplex(method, _, _, responder) => {
+            let _ = responder.error(&no_handler(&method));
+        }
+        IncomingRpc::Sink(method, _, responder) => {
+            let _ = responder.error(&no_handler(&method));
+        }
+        // muxrpc maps `sync` onto `async` on the wire, so no `Sync` frames
+        // reach us here; guard against future variants rather than panicking.
+        _ => {}
+    }
+}
+
+fn path_of(method: &[String]) -> Method {
+    method.to_vec()
+}
+
+/// The error body returned when a method has no registered handler, matching
+/// the shape muxrpc peers expect (`{ name, message }`).
+fn no_handler(method: &[String]) -> Value {
+    json!({
+        "name": "METHOD_NOT_FOUND",
+        "message": format!("no handler for method {:?}", method),
+    })
+}